@@ -10,6 +10,12 @@ use clap::ValueEnum;
 
 const SPARSE_MAGIC: [u8; 4] = [0x3A, 0xFF, 0x26, 0xED];
 
+const MISC_SIZE: usize = 1_048_576;
+/// Offset of `struct bootloader_control` inside the `slot_suffix` region of
+/// `bootloader_message_ab`.
+const BOOTLOADER_CONTROL_OFFSET: usize = 2048;
+const BOOTLOADER_CONTROL_MAGIC: u32 = 0x42414342;
+
 #[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
 pub enum Arch {
     X86_64,
@@ -181,62 +187,108 @@ pub fn create_vbmeta(
     Ok(())
 }
 
+/// User-supplied changes to the default bootconfig property set: individual
+/// key overrides/removals, plus a file of extra raw `androidboot.*`/`kernel`
+/// lines to append verbatim, following how `assemble_cvd` exposes these
+/// bootconfig knobs as flags.
+#[derive(Default)]
+pub struct BootconfigOverrides {
+    pub set: Vec<(String, String)>,
+    pub unset: Vec<String>,
+    pub extra_file: Option<PathBuf>,
+}
+
+/// The default `androidboot.*` property set, in the order they're emitted,
+/// with the arch- and virgl-conditional properties folded in as overridable
+/// defaults rather than separate byte blobs.
+fn default_bootconfig_properties(arch: &Arch, virgl: bool) -> Vec<(String, String)> {
+    let mut props: Vec<(String, String)> = [
+        ("androidboot.hypervisor.protected_vm.supported", "0"),
+        ("androidboot.modem_simulator_ports", "9600"),
+        ("androidboot.lcd_density", "320"),
+        ("androidboot.vendor.audiocontrol.server.port", "9410"),
+        ("androidboot.vendor.audiocontrol.server.cid", "3"),
+        ("androidboot.cuttlefish_config_server_port", "6800"),
+        ("androidboot.vendor.vehiclehal.server.port", "9300"),
+        ("androidboot.fstab_suffix", "cf.f2fs.hctr2"),
+        ("androidboot.enable_confirmationui", "0"),
+        ("androidboot.hypervisor.vm.supported", "0"),
+        ("androidboot.serialno", "CUTTLEFISHCVD011"),
+        ("androidboot.setupwizard_mode", "DISABLED"),
+        ("androidboot.cpuvulkan.version", "4202496"),
+        ("androidboot.ddr_size", "4915MB"),
+        (
+            "androidboot.hardware.angle_feature_overrides_enabled",
+            "preferLinearFilterForYUV:mapUnspecifiedColorSpaceToPassThrough",
+        ),
+        ("androidboot.enable_bootanimation", "1"),
+        ("androidboot.hardware.gralloc", "minigbm"),
+        ("androidboot.vendor.vehiclehal.server.cid", "2"),
+        ("androidboot.hypervisor.version", "cf-qemu_cli"),
+        ("androidboot.hardware.vulkan", "pastel"),
+        ("androidboot.opengles.version", "196609"),
+        ("androidboot.wifi_mac_prefix", "5554"),
+        ("androidboot.vsock_tombstone_port", "6600"),
+        ("androidboot.hardware.hwcomposer", "ranchu"),
+        ("androidboot.serialconsole", "0"),
+    ]
+    .into_iter()
+    .map(|(k, v)| (k.to_string(), v.to_string()))
+    .collect();
+
+    props.push((
+        "androidboot.boot_devices".to_string(),
+        match arch {
+            Arch::X86_64 => "pci0000:00/0000:00:0f.0,pci0000:00/0000:00:10.0".to_string(),
+            Arch::Aarch64 => "4010000000.pcie".to_string(),
+        },
+    ));
+
+    if virgl {
+        props.push(("androidboot.hardware.egl".to_string(), "mesa".to_string()));
+        props.push((
+            "androidboot.hardware.hwcomposer.display_finder_mode".to_string(),
+            "drm".to_string(),
+        ));
+        props.push((
+            "androidboot.hardware.hwcomposer.mode".to_string(),
+            "client".to_string(),
+        ));
+    } else {
+        props.push(("androidboot.hardware.egl".to_string(), "angle".to_string()));
+    }
+
+    props
+}
+
 pub fn create_bootconfig(
     cvd_dir: &Path,
     tmp_dir: &Path,
     envs: &HashMap<&str, &PathBuf>,
     arch: &Arch,
     virgl: bool,
+    overrides: &BootconfigOverrides,
 ) -> Result<(), std::io::Error> {
-    let props_base = b"androidboot.hypervisor.protected_vm.supported=0
-androidboot.modem_simulator_ports=9600
-androidboot.lcd_density=320
-androidboot.vendor.audiocontrol.server.port=9410
-androidboot.vendor.audiocontrol.server.cid=3
-androidboot.cuttlefish_config_server_port=6800
-androidboot.vendor.vehiclehal.server.port=9300
-androidboot.fstab_suffix=cf.f2fs.hctr2
-androidboot.enable_confirmationui=0
-androidboot.hypervisor.vm.supported=0
-androidboot.serialno=CUTTLEFISHCVD011
-androidboot.setupwizard_mode=DISABLED
-androidboot.cpuvulkan.version=4202496
-androidboot.ddr_size=4915MB
-androidboot.hardware.angle_feature_overrides_enabled=preferLinearFilterForYUV:mapUnspecifiedColorSpaceToPassThrough
-androidboot.enable_bootanimation=1
-androidboot.hardware.gralloc=minigbm
-androidboot.vendor.vehiclehal.server.cid=2
-androidboot.hypervisor.version=cf-qemu_cli
-androidboot.hardware.vulkan=pastel
-androidboot.opengles.version=196609
-androidboot.wifi_mac_prefix=5554
-androidboot.vsock_tombstone_port=6600
-androidboot.hardware.hwcomposer=ranchu
-androidboot.serialconsole=0
-";
-    let props_boot_x86_64 =
-        b"androidboot.boot_devices=pci0000:00/0000:00:0f.0,pci0000:00/0000:00:10.0
-";
-    let props_boot_aarch64 = b"androidboot.boot_devices=4010000000.pcie
-";
-    let props_render_sw = b"androidboot.hardware.egl=angle
-";
-    let props_render_virgl = b"androidboot.hardware.egl=mesa
-androidboot.hardware.hwcomposer.display_finder_mode=drm
-androidboot.hardware.hwcomposer.mode=client
-";
+    let mut props = default_bootconfig_properties(arch, virgl);
+
+    for (key, value) in &overrides.set {
+        match props.iter_mut().find(|(k, _)| k == key) {
+            Some(existing) => existing.1 = value.clone(),
+            None => props.push((key.clone(), value.clone())),
+        }
+    }
+    props.retain(|(key, _)| !overrides.unset.contains(key));
 
     let bootconfig_path = tmp_dir.join("bootconfig");
     let mut f = File::create(&bootconfig_path)?;
-    f.write_all(props_base)?;
-    match arch {
-        Arch::X86_64 => f.write_all(props_boot_x86_64)?,
-        Arch::Aarch64 => f.write_all(props_boot_aarch64)?,
-    };
-    if virgl {
-        f.write_all(props_render_virgl)?;
-    } else {
-        f.write_all(props_render_sw)?;
+    for (key, value) in &props {
+        writeln!(f, "{key}={value}")?;
+    }
+    if let Some(extra_file) = &overrides.extra_file {
+        let extra = std::fs::read_to_string(extra_file)?;
+        for line in extra.lines() {
+            writeln!(f, "{line}")?;
+        }
     }
     drop(f);
 
@@ -269,3 +321,164 @@ androidboot.hardware.hwcomposer.mode=client
 
     Ok(())
 }
+
+/// One slot of the `bootloader_control` `slot_metadata` array: `priority`
+/// (bits 0-3), `tries_remaining` (bits 4-6) and `successful_boot` (bit 7),
+/// followed by a reserved `verity_corrupted` byte.
+struct SlotMetadata {
+    priority: u8,
+    tries_remaining: u8,
+    successful_boot: bool,
+}
+
+fn slot_metadata_bytes(slot: &SlotMetadata) -> [u8; 2] {
+    let packed = (slot.priority & 0x0F)
+        | ((slot.tries_remaining & 0x07) << 4)
+        | ((slot.successful_boot as u8) << 7);
+    [packed, 0]
+}
+
+/// Builds the `misc` partition image: a blank 1 MiB buffer with an A/B
+/// `bootloader_control` block written at `BOOTLOADER_CONTROL_OFFSET`, so
+/// U-Boot's `ab_select` has slot metadata to read. Slot A starts out as the
+/// preferred, already-successful boot slot; slot B is kept as a
+/// lower-priority fallback that hasn't booted yet.
+pub fn create_misc_ab() -> Vec<u8> {
+    let mut image = vec![0u8; MISC_SIZE];
+
+    let mut control = Vec::with_capacity(28);
+    control.extend_from_slice(&[0u8; 4]); // slot_suffix
+    control.extend_from_slice(&BOOTLOADER_CONTROL_MAGIC.to_le_bytes());
+    control.push(1); // version
+    control.push(2); // nb_slot = 2 (bits 0-2), recovery_tries_remaining = 0 (bits 3-5)
+    control.extend_from_slice(&[0u8; 2]); // reserved, pads slot_info to 4-byte alignment
+
+    let slot_a = SlotMetadata {
+        priority: 15,
+        tries_remaining: 7,
+        successful_boot: true,
+    };
+    let slot_b = SlotMetadata {
+        priority: 14,
+        tries_remaining: 7,
+        successful_boot: false,
+    };
+    control.extend_from_slice(&slot_metadata_bytes(&slot_a));
+    control.extend_from_slice(&slot_metadata_bytes(&slot_b));
+    control.extend_from_slice(&[0u8; 2]); // unused slot C
+    control.extend_from_slice(&[0u8; 2]); // unused slot D
+
+    control.extend_from_slice(&[0u8; 8]); // reserved
+
+    let crc = crc32fast::hash(&control);
+    control.extend_from_slice(&crc.to_le_bytes());
+
+    image[BOOTLOADER_CONTROL_OFFSET..BOOTLOADER_CONTROL_OFFSET + control.len()]
+        .copy_from_slice(&control);
+
+    image
+}
+
+/// Alternate partition images to inject into `super.img` before it's
+/// assembled into the system disk, mirroring assemble_cvd's
+/// super_image_mixer/vendor_dlkm step.
+#[derive(Default)]
+pub struct SuperMixTargets {
+    pub system_target: Option<PathBuf>,
+    pub vendor_target: Option<PathBuf>,
+}
+
+fn lprm(
+    cvd_dir: &Path,
+    envs: &HashMap<&str, &PathBuf>,
+    super_image: &Path,
+    partition_name: &str,
+) {
+    // The partition may not exist yet on a base image that's never been
+    // mixed before, so a non-zero exit here is expected and ignored. A
+    // missing lprm binary is still worth surfacing, though.
+    match Command::new(cvd_dir.join("bin/lprm"))
+        .arg(super_image)
+        .arg(partition_name)
+        .envs(envs)
+        .stderr(std::process::Stdio::null())
+        .output()
+    {
+        Ok(_) => (),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+            println!("Can't find lprm in {}", cvd_dir.display());
+        }
+        Err(_) => (),
+    }
+}
+
+fn lpadd(
+    cvd_dir: &Path,
+    envs: &HashMap<&str, &PathBuf>,
+    super_image: &Path,
+    partition_name: &str,
+    image: &Path,
+) -> Result<(), std::io::Error> {
+    let output = match Command::new(cvd_dir.join("bin/lpadd"))
+        .arg(super_image)
+        .arg(partition_name)
+        .arg(image)
+        .envs(envs)
+        .stderr(std::process::Stdio::inherit())
+        .output()
+    {
+        Ok(output) => output,
+        Err(err) => {
+            if err.kind() == std::io::ErrorKind::NotFound {
+                println!("Can't find lpadd in {}", cvd_dir.display());
+            } else {
+                println!("Error executing lpadd: {err}");
+            }
+            std::process::exit(-1);
+        }
+    };
+
+    if !output.status.success() {
+        return Err(std::io::Error::other(format!(
+            "lpadd failed to add partition {partition_name} to {}: {}",
+            super_image.display(),
+            output.status
+        )));
+    }
+
+    Ok(())
+}
+
+/// Rebuilds `super.img` with the `system_a`/`vendor_a` dynamic partitions
+/// replaced by `targets.system_target`/`targets.vendor_target`, so a base
+/// Cuttlefish build can be combined with a locally built vendor image or GSI
+/// system before the disk is assembled. Reads the liblp metadata already
+/// present at the head of the base `super.img` and edits it in place via the
+/// bundled `lprm`/`lpadd` rather than rebuilding the geometry from scratch.
+/// Returns the path to the mixed image in `tmp_dir`, or the untouched
+/// `cvd_dir/super.img` if neither target is set.
+pub fn mix_super_image(
+    cvd_dir: &Path,
+    tmp_dir: &Path,
+    envs: &HashMap<&str, &PathBuf>,
+    targets: &SuperMixTargets,
+) -> Result<PathBuf, std::io::Error> {
+    let base_super = cvd_dir.join("super.img");
+    if targets.system_target.is_none() && targets.vendor_target.is_none() {
+        return Ok(base_super);
+    }
+
+    let mixed_super = tmp_dir.join("super.img");
+    std::fs::copy(&base_super, &mixed_super)?;
+
+    if let Some(system_target) = &targets.system_target {
+        lprm(cvd_dir, envs, &mixed_super, "system_a");
+        lpadd(cvd_dir, envs, &mixed_super, "system_a", system_target)?;
+    }
+    if let Some(vendor_target) = &targets.vendor_target {
+        lprm(cvd_dir, envs, &mixed_super, "vendor_a");
+        lpadd(cvd_dir, envs, &mixed_super, "vendor_a", vendor_target)?;
+    }
+
+    Ok(mixed_super)
+}
@@ -1,99 +1,212 @@
-use std::io::{Read, Write};
-use std::{fs::File, path::Path};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    path::{Path, PathBuf},
+};
 
-use libparted::{Device, Disk, DiskType, FileSystemType, Partition, PartitionType};
+use gpt::{disk::LogicalBlockSize, partition::Partition, partition_types, GptConfig};
+use uuid::Uuid;
 
-fn best_block_size(size: u64) -> usize {
-    let mut bs = 1048576;
-    loop {
-        if size > bs && (size % bs) == 0 {
-            return bs.try_into().unwrap();
-        }
-        bs /= 2;
+use crate::components::create_misc_ab;
+
+const LBA_SIZE: u64 = 512;
+
+/// First usable LBA, 1 MiB into the disk: an alignment that leaves room for
+/// the protective MBR, primary GPT header and partition table (34 LBAs at
+/// 512 bytes each). Partition placement below and the GPT table written by
+/// `create_partitions` both derive from this single constant, so the raw
+/// byte layout and the partition table can never disagree about where a
+/// partition starts.
+const FIRST_USABLE_LBA: u64 = 2048;
+
+/// Trailing space reserved for the backup GPT header and partition table,
+/// at the same 1 MiB alignment as the front of the disk.
+const GPT_FOOTER_BYTES: u64 = FIRST_USABLE_LBA * LBA_SIZE;
+
+/// Namespace used to derive a stable, reproducible partition GUID from a
+/// partition name, so that re-running cvd2img on the same `cvd_dir` always
+/// gives each partition the same GUID. The disk GUID in the GPT header is
+/// left to `GptConfig::create`.
+const PARTITION_GUID_NAMESPACE: Uuid = Uuid::from_bytes([
+    0x3b, 0x5f, 0x1a, 0x8e, 0x0c, 0x4d, 0x4b, 0x8a, 0x9a, 0x2e, 0x7c, 0x1d, 0x6a, 0x9f, 0x2b, 0x44,
+]);
+
+/// Android/AVB partition type GUIDs, keyed by the base partition name (i.e.
+/// with any `_a`/`_b` slot suffix stripped). AOSP's reference bootloaders
+/// don't register these with the UEFI GPT type GUID registry, so each
+/// Android-derived project mints its own, the same way Fuchsia's
+/// make-fuchsia-vol does for its own partition scheme.
+const PARTITION_TYPE_GUIDS: &[(&str, &str)] = &[
+    ("misc", "ef32a33b-a409-486c-9141-9ffb711f6266"),
+    ("boot", "20117f86-e985-4357-b9ee-374bc1d8487d"),
+    ("init_boot", "124c8907-8c27-4f34-bb7a-1c3a0b6b9b5b"),
+    ("vendor_boot", "c57ab9e1-a0ee-4e6e-b52b-d49a4fdb3dc7"),
+    ("vbmeta", "d1f87a14-8d33-43e3-991f-f3674d3b4c0e"),
+    ("vbmeta_system", "a5d9f3c0-5d54-4c4d-9a77-6e1ea07f0d4e"),
+    ("vbmeta_vendor_dlkm", "9e6a2c3f-4b6a-4a57-9a5a-4b6b1e4a5f0c"),
+    ("vbmeta_system_dlkm", "b2d6c4a1-9f3e-4a9a-8c2d-1f6e9b4a7d3c"),
+    ("super", "0f2778c4-5cc1-4300-8670-6c355231e4a9"),
+    ("userdata", "0fc63daf-8483-4772-8e79-3d69d8477de4"),
+    ("metadata", "0fc63daf-8483-4772-8e79-3d69d8477de4"),
+    ("uboot_env", "2568845d-2332-4675-bc39-8fa5a4748d15"),
+    ("frp", "09a33d5a-f083-4e7f-8bff-3ddee38a2c8f"),
+    ("bootconfig", "d91aa0f5-8df2-4e6b-8f0c-4f6fa8a3a4b0"),
+];
+
+/// Falls back to the generic "Linux filesystem data" GUID for anything we
+/// don't have a dedicated entry for.
+const LINUX_FS_GUID: &str = "0fc63daf-8483-4772-8e79-3d69d8477de4";
+
+fn base_name(name: &str) -> &str {
+    name.strip_suffix("_a")
+        .or_else(|| name.strip_suffix("_b"))
+        .unwrap_or(name)
+}
+
+fn partition_type_for(name: &str) -> partition_types::Type {
+    let guid = PARTITION_TYPE_GUIDS
+        .iter()
+        .find(|(base, _)| *base == base_name(name))
+        .map(|(_, guid)| *guid)
+        .unwrap_or(LINUX_FS_GUID);
+
+    partition_types::Type {
+        guid,
+        os: partition_types::OperatingSystem::Linux,
     }
 }
 
+/// Derives a stable, reproducible partition GUID from its name, so that
+/// re-running cvd2img over the same inputs always produces the same
+/// partition GUIDs.
+fn partition_guid_for(name: &str) -> Uuid {
+    Uuid::new_v5(&PARTITION_GUID_NAMESPACE, name.as_bytes())
+}
+
+/// Buffer size for streaming a component's bytes into the disk image. The
+/// copy loop below honors the exact byte count `read` returns on a short
+/// final chunk, so this need not evenly divide the source size.
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Number of 512-byte LBAs needed to hold `size` bytes.
+fn sectors_for(size: u64) -> u64 {
+    (size + LBA_SIZE - 1) / LBA_SIZE
+}
+
+/// Resolves the source path for a non-blank, non-generated component.
+fn component_path(cvd_dir: &Path, source_overrides: &HashMap<&str, PathBuf>, image: &str) -> PathBuf {
+    source_overrides
+        .get(image)
+        .cloned()
+        .unwrap_or_else(|| cvd_dir.join(image))
+}
+
 pub fn create_disk_image<'a>(
     cvd_dir: &Path,
     components: &[(&'a str, &'a str)],
+    source_overrides: &HashMap<&str, PathBuf>,
     out_file: &Path,
-) -> std::io::Result<Vec<(&'a str, &'a str, u64)>> {
-    let mut parts = Vec::new();
-    let mut out = File::create(out_file)?;
-
-    let zeroes = vec![0; 20480];
-
-    // Space reserved for GPT header
-    out.write_all(&zeroes)?;
+) -> std::io::Result<Vec<(&'a str, &'a str, u64, u64)>> {
+    // First pass: resolve every component's size (and stash any in-memory
+    // generated content) and lay out each partition's start LBA
+    // sequentially from FIRST_USABLE_LBA. create_partitions builds the GPT
+    // table from these exact same (size, start_lba) pairs, so the two can
+    // never disagree about where a partition lives.
+    let mut generated: HashMap<&str, Vec<u8>> = HashMap::new();
+    let mut parts: Vec<(&str, &str, u64, u64)> = Vec::new();
+    let mut start_lba = FIRST_USABLE_LBA;
 
     for (image, name) in components {
         let size = if image.contains("blank") {
             let elems: Vec<&str> = image.split(':').collect();
-            let size: u64 = (elems[1]).parse::<u64>().unwrap();
-            let bs = best_block_size(size);
-            let buf = vec![0u8; best_block_size(size)];
-            let mut written = 0;
-            loop {
-                out.write_all(&buf)?;
-                written += bs;
-                if written >= size.try_into().unwrap() {
-                    break;
-                }
-            }
+            elems[1].parse::<u64>().unwrap()
+        } else if *image == "misc_ab" {
+            let buf = create_misc_ab();
+            let size = buf.len() as u64;
+            generated.insert(*name, buf);
             size
         } else {
-            let mut src = File::open(cvd_dir.join(image))?;
-            let metadata = src.metadata()?;
-            let size = metadata.len();
-            println!("image: {image} len={size}");
-            let mut buf = vec![0u8; best_block_size(size)];
-            let mut written = 0;
-            loop {
-                let n = src.read(&mut buf)?;
-                out.write_all(&buf)?;
-                written += n;
-                if written >= size.try_into().unwrap() {
-                    break;
-                }
-            }
-            size
+            File::open(component_path(cvd_dir, source_overrides, image))?
+                .metadata()?
+                .len()
         };
-        parts.push((*image, *name, size));
+
+        parts.push((*image, *name, size, start_lba));
+        start_lba += sectors_for(size);
     }
 
-    // Space reserved for GPT footer
-    out.write_all(&zeroes)?;
+    let total_size = start_lba * LBA_SIZE + GPT_FOOTER_BYTES;
+    let mut out = File::create(out_file)?;
+    out.set_len(total_size)?;
+
+    for (image, name, size, part_start_lba) in &parts {
+        if image.contains("blank") {
+            // Left as a sparse hole: the set_len above already makes this
+            // range read back as zeros, so there's nothing to write.
+            continue;
+        }
+
+        out.seek(SeekFrom::Start(part_start_lba * LBA_SIZE))?;
+
+        if *image == "misc_ab" {
+            out.write_all(&generated[name])?;
+            continue;
+        }
+
+        let mut src = File::open(component_path(cvd_dir, source_overrides, image))?;
+        println!("image: {image} len={size}");
+        let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+        let mut written: u64 = 0;
+        loop {
+            let n = src.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            out.write_all(&buf[..n])?;
+            written += n as u64;
+            if written >= *size {
+                break;
+            }
+        }
+    }
 
     Ok(parts)
 }
 
 pub fn create_partitions(
-    parts: Vec<(&str, &str, u64)>,
+    parts: Vec<(&str, &str, u64, u64)>,
     out_file: &Path,
 ) -> Result<(), std::io::Error> {
-    let mut dev = Device::new(out_file)?;
-    let mut disk = Disk::new_fresh(&mut dev, DiskType::get("gpt").unwrap())?;
-
-    let constraint = disk.constraint_any().unwrap();
-
-    let mut start_sector = 40;
-
-    for p in parts {
-        let len: i64 = (((p.2 - 1) / 512) + 1).try_into().unwrap();
-        Partition::new(
-            &disk,
-            PartitionType::PED_PARTITION_NORMAL,
-            Some(&FileSystemType::get("ext2").unwrap()),
-            start_sector,
-            start_sector + len - 1,
-        )
-        .and_then(|mut part| {
-            part.set_name(p.1).unwrap();
-            disk.add_partition(&mut part, &constraint)
-        })?;
-
-        start_sector += len;
-    }
+    let mut disk = GptConfig::new()
+        .writable(true)
+        .logical_block_size(LogicalBlockSize::Lb512)
+        .create(out_file)?;
+
+    let table: BTreeMap<u32, Partition> = parts
+        .iter()
+        .enumerate()
+        .map(|(index, (_, name, size, start_lba))| {
+            let partition = Partition {
+                part_type_guid: partition_type_for(name),
+                part_guid: partition_guid_for(name),
+                first_lba: *start_lba,
+                last_lba: start_lba + sectors_for(*size) - 1,
+                flags: 0,
+                name: (*name).to_string(),
+            };
+            (index as u32 + 1, partition)
+        })
+        .collect();
+
+    // Writes the exact same start/end LBAs create_disk_image already wrote
+    // each partition's bytes to, rather than letting the crate's own
+    // placement logic pick (possibly different) offsets.
+    disk.update_partitions(table).map_err(std::io::Error::other)?;
+
+    // Writes the protective MBR plus the primary and backup GPT headers
+    // (with their CRC32 checksums) and partition tables.
+    disk.write()?;
 
-    disk.commit()
+    Ok(())
 }
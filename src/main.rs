@@ -10,10 +10,19 @@ use tempdir::TempDir;
 mod disk;
 use disk::{create_disk_image, create_partitions};
 mod components;
-use components::{create_bootconfig, create_uboot, create_vbmeta, transform_sparse_images, Arch};
+use components::{
+    create_bootconfig, create_uboot, create_vbmeta, mix_super_image, transform_sparse_images,
+    Arch, BootconfigOverrides, SuperMixTargets,
+};
+
+fn parse_bootconfig_kv(s: &str) -> Result<(String, String), String> {
+    s.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("invalid KEY=VALUE: no `=` found in `{s}`"))
+}
 
 const SYSTEM_COMPONENTS: &[(&str, &str)] = &[
-    ("blank:1048576", "misc"),
+    ("misc_ab", "misc"),
     ("boot.img", "boot_a"),
     ("boot.img", "boot_b"),
     ("init_boot.img", "init_boot_a"),
@@ -45,6 +54,7 @@ enum Error {
     Bootconfig(std::io::Error),
     DiskImage(std::io::Error),
     Partitions(std::io::Error),
+    SuperMix(std::io::Error),
     TransformSparse(std::io::Error),
     Uboot(std::io::Error),
     Vbmeta(std::io::Error),
@@ -79,6 +89,27 @@ struct Arguments {
     /// Output file for the virgl variant of the properties disk image
     #[arg(short, long, value_name = "FILE")]
     virgl_props: Option<PathBuf>,
+
+    /// Override a bootconfig property, e.g. `androidboot.lcd_density=480`
+    /// (repeatable)
+    #[arg(long = "bootconfig-set", value_name = "KEY=VALUE", value_parser = parse_bootconfig_kv)]
+    bootconfig_set: Vec<(String, String)>,
+
+    /// Drop a bootconfig property by key (repeatable)
+    #[arg(long = "bootconfig-unset", value_name = "KEY")]
+    bootconfig_unset: Vec<String>,
+
+    /// File with extra androidboot.*/kernel bootconfig lines to append
+    #[arg(long = "bootconfig-extra-file", value_name = "FILE")]
+    bootconfig_extra_file: Option<PathBuf>,
+
+    /// Alternate system partition image to mix into super.img
+    #[arg(long, value_name = "FILE")]
+    system_target: Option<PathBuf>,
+
+    /// Alternate vendor partition image to mix into super.img
+    #[arg(long, value_name = "FILE")]
+    vendor_target: Option<PathBuf>,
 }
 
 fn create_disk_images(args: Arguments) -> Result<(), Error> {
@@ -104,30 +135,69 @@ fn create_disk_images(args: Arguments) -> Result<(), Error> {
         ("ANDROID_ROOT", &cvd_dir),
     ]);
 
+    let bootconfig_overrides = BootconfigOverrides {
+        set: args.bootconfig_set.clone(),
+        unset: args.bootconfig_unset.clone(),
+        extra_file: args.bootconfig_extra_file.clone(),
+    };
+    let super_mix_targets = SuperMixTargets {
+        system_target: args.system_target.clone(),
+        vendor_target: args.vendor_target.clone(),
+    };
+
     println!("Transforming sparse images if needed");
     transform_sparse_images(&cvd_dir, &envs).map_err(Error::TransformSparse)?;
 
+    let tmp_dir = TempDir::new("cvd2img").unwrap().into_path();
+
+    let mut system_sources = HashMap::new();
+    if super_mix_targets.system_target.is_some() || super_mix_targets.vendor_target.is_some() {
+        println!("Mixing super.img");
+        let mixed_super = mix_super_image(&cvd_dir, &tmp_dir, &envs, &super_mix_targets)
+            .map_err(Error::SuperMix)?;
+        system_sources.insert("super.img", mixed_super);
+    }
+
     println!("Creating {} disk image", out_system.display());
-    let parts =
-        create_disk_image(&cvd_dir, SYSTEM_COMPONENTS, &out_system).map_err(Error::DiskImage)?;
+    let parts = create_disk_image(&cvd_dir, SYSTEM_COMPONENTS, &system_sources, &out_system)
+        .map_err(Error::DiskImage)?;
     create_partitions(parts, &out_system).map_err(Error::Partitions)?;
 
-    let tmp_dir = TempDir::new("cvd2img").unwrap().into_path();
-
     println!("Creating persistent components");
     create_uboot(&cvd_dir, &tmp_dir, &envs).map_err(Error::Uboot)?;
     create_vbmeta(&cvd_dir, &tmp_dir, &envs).map_err(Error::Vbmeta)?;
-    create_bootconfig(&cvd_dir, &tmp_dir, &envs, &arch, false).map_err(Error::Bootconfig)?;
+    create_bootconfig(
+        &cvd_dir,
+        &tmp_dir,
+        &envs,
+        &arch,
+        false,
+        &bootconfig_overrides,
+    )
+    .map_err(Error::Bootconfig)?;
 
     println!("Creating {} disk image", out_props.display());
-    let parts =
-        create_disk_image(&tmp_dir, PROPERTIES_COMPONENTS, &out_props).map_err(Error::DiskImage)?;
+    let parts = create_disk_image(&tmp_dir, PROPERTIES_COMPONENTS, &HashMap::new(), &out_props)
+        .map_err(Error::DiskImage)?;
     create_partitions(parts, &out_props).map_err(Error::Partitions)?;
 
-    create_bootconfig(&cvd_dir, &tmp_dir, &envs, &arch, true).map_err(Error::Bootconfig)?;
+    create_bootconfig(
+        &cvd_dir,
+        &tmp_dir,
+        &envs,
+        &arch,
+        true,
+        &bootconfig_overrides,
+    )
+    .map_err(Error::Bootconfig)?;
     println!("Creating {} disk image", out_virgl_props.display());
-    let parts = create_disk_image(&tmp_dir, PROPERTIES_COMPONENTS, &out_virgl_props)
-        .map_err(Error::DiskImage)?;
+    let parts = create_disk_image(
+        &tmp_dir,
+        PROPERTIES_COMPONENTS,
+        &HashMap::new(),
+        &out_virgl_props,
+    )
+    .map_err(Error::DiskImage)?;
     create_partitions(parts, &out_virgl_props).map_err(Error::Partitions)?;
 
     Ok(())